@@ -1,5 +1,20 @@
 // Copyright 2022 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
+//
+// NB: This file reads fields on `ExecutionEnvironment` (reached via `Process::execution_environment`)
+// and `Context` that aren't defined anywhere in this checkout: `namespace_sandbox`, `use_jobserver`,
+// `shutdown_style`, `enable_output_streaming`, and `output_streaming_settings` on the former,
+// `output_sink` on the latter. Each is marked with a `// NB: requires upstream field` comment at its
+// read site below so the exact list can be grepped for rather than taken on faith here. `Process`
+// and `Context` themselves are imported from `crate` (see the `use` block just below) -- their
+// definitions live in sibling modules this patch series doesn't include, so these fields need to
+// be added there in lockstep for this file to compile against the full crate. Separately,
+// `CommandRunner::new` gained a `jobserver` parameter over the course of this series; any existing
+// caller of it elsewhere in the crate (also outside this checkout) needs updating to pass one.
+// This file can't supply either on its own: there's nowhere in this checkout to put them without
+// guessing at the real shape of types this crate doesn't define here, which would be worse than
+// leaving the gap explicit. Treat a compile failure against the full crate at one of the marked
+// sites as confirmation that its companion edit is still outstanding, not that the usage is wrong.
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{self, Debug, Display};
 use std::io::Write;
@@ -7,7 +22,6 @@ use std::ops::Neg;
 use std::os::unix::{fs::OpenOptionsExt, process::ExitStatusExt};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::str;
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -25,7 +39,6 @@ use log::{debug, info};
 use nails::execution::ExitCode;
 use sandboxer::Sandboxer;
 use serde::Serialize;
-use shell_quote::Bash;
 use store::{
     ImmutableInputs, OneOffStoreFileByDigest, Snapshot, SnapshotOps, Store, WorkdirSymlink,
 };
@@ -63,6 +76,9 @@ pub struct CommandRunner {
     named_caches: NamedCaches,
     immutable_inputs: ImmutableInputs,
     spawn_lock: Arc<RwLock<()>>,
+    jobserver: Option<Arc<Jobserver>>,
+    supports_namespace_sandbox: bool,
+    supports_privileged_namespace_sandbox: bool,
 }
 
 impl CommandRunner {
@@ -74,6 +90,10 @@ impl CommandRunner {
         named_caches: NamedCaches,
         immutable_inputs: ImmutableInputs,
         spawn_lock: Arc<RwLock<()>>,
+        // NB: requires upstream caller update -- this parameter was added over the course of this
+        // patch series; any existing construction of `CommandRunner` elsewhere in the crate needs
+        // to start passing one.
+        jobserver: Option<Arc<Jobserver>>,
     ) -> CommandRunner {
         CommandRunner {
             store,
@@ -83,6 +103,9 @@ impl CommandRunner {
             named_caches,
             immutable_inputs,
             spawn_lock,
+            jobserver,
+            supports_namespace_sandbox: namespace_sandbox_available(),
+            supports_privileged_namespace_sandbox: privileged_namespace_sandbox_available(),
         }
     }
 
@@ -162,20 +185,142 @@ pub enum ChildOutput {
     Exit(ExitCode),
 }
 
+/// A sink that incremental process output is forwarded to once a stream has transitioned out of
+/// `Buffering` mode. Implementations are expected to be cheap to invoke, since they are called
+/// once per chunk read from the child's stdout/stderr pipes.
 ///
-/// Collect the outputs of a child process.
+/// Ordering within a single stream (all `Stdout` calls, or all `Stderr` calls) matches the order
+/// the child wrote those bytes. There is no such guarantee *across* streams: `observed_at` is
+/// provided so that a consumer rendering both streams together (e.g. to a console log) can
+/// interleave them in a best-effort way, similar to `cargo-util`'s `read2`.
+pub trait OutputSink: Send + Sync {
+    fn emit(&self, output: &ChildOutput, observed_at: Instant);
+}
+
+/// Governs when a stream switches from atomically buffering output (so that fast processes are
+/// captured-and-digested with no interleaving noise) to streaming each chunk to an `OutputSink`
+/// as it arrives.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputStreamingSettings {
+    /// How long to buffer a stream before switching it to `Streaming` mode.
+    pub max_buffer_time: std::time::Duration,
+    /// How many bytes to buffer for a stream before switching it to `Streaming` mode.
+    pub max_buffer_bytes: usize,
+}
+
+impl Default for OutputStreamingSettings {
+    fn default() -> Self {
+        Self {
+            max_buffer_time: std::time::Duration::from_millis(100),
+            max_buffer_bytes: 8192,
+        }
+    }
+}
+
+impl OutputStreamingSettings {
+    /// Skips buffering entirely: the very first chunk read from each stream is immediately
+    /// forwarded to the sink. Used when a `Process` has opted into live streaming outright,
+    /// rather than only after the buffering threshold is exceeded.
+    pub fn immediate() -> Self {
+        Self {
+            max_buffer_time: std::time::Duration::ZERO,
+            max_buffer_bytes: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StreamMode {
+    Buffering,
+    Streaming,
+}
+
+/// Per-stream bookkeeping for the `Buffering` -> `Streaming` transition. The transition is
+/// tracked independently for stdout and stderr, so that a chatty stderr doesn't force stdout to
+/// start streaming early (or vice versa).
+struct StreamState {
+    mode: StreamMode,
+    started_buffering_at: Instant,
+    buffered_bytes: usize,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            mode: StreamMode::Buffering,
+            started_buffering_at: Instant::now(),
+            buffered_bytes: 0,
+        }
+    }
+
+    /// Tees `chunk` into `digest_buffer` (which always receives every byte, so that the final
+    /// digest is unaffected by streaming), and, once this stream has switched to `Streaming`
+    /// mode, forwards it (or, on the chunk that triggers the transition, the buffered prefix
+    /// followed by this chunk) to `sink`.
+    fn tee(
+        &mut self,
+        digest_buffer: &mut BytesMut,
+        chunk: Bytes,
+        wrap: impl Fn(Bytes) -> ChildOutput,
+        settings: &OutputStreamingSettings,
+        sink: Option<&dyn OutputSink>,
+    ) {
+        digest_buffer.extend_from_slice(&chunk);
+
+        if self.mode == StreamMode::Buffering {
+            self.buffered_bytes += chunk.len();
+            if self.buffered_bytes < settings.max_buffer_bytes
+                && self.started_buffering_at.elapsed() < settings.max_buffer_time
+            {
+                return;
+            }
+            self.mode = StreamMode::Streaming;
+            if let Some(sink) = sink {
+                // Flush everything buffered so far for this stream before switching to
+                // per-chunk emission.
+                sink.emit(&wrap(Bytes::copy_from_slice(digest_buffer)), Instant::now());
+            }
+            return;
+        }
+
+        if let Some(sink) = sink {
+            sink.emit(&wrap(chunk), Instant::now());
+        }
+    }
+}
+
+///
+/// Collect the outputs of a child process, tee-ing each chunk into the digest buffers (for the
+/// final, complete capture) and, once a stream has passed `streaming_settings`' buffering
+/// threshold, into `output_sink` as it arrives (for live console logs).
 ///
 pub async fn collect_child_outputs<'a>(
     stdout: &'a mut BytesMut,
     stderr: &'a mut BytesMut,
     mut stream: BoxStream<'_, Result<ChildOutput, String>>,
+    streaming_settings: OutputStreamingSettings,
+    output_sink: Option<&dyn OutputSink>,
 ) -> Result<i32, String> {
     let mut exit_code = 1;
+    let mut stdout_state = StreamState::new();
+    let mut stderr_state = StreamState::new();
 
     while let Some(child_output_res) = stream.next().await {
         match child_output_res? {
-            ChildOutput::Stdout(bytes) => stdout.extend_from_slice(&bytes),
-            ChildOutput::Stderr(bytes) => stderr.extend_from_slice(&bytes),
+            ChildOutput::Stdout(bytes) => stdout_state.tee(
+                stdout,
+                bytes,
+                ChildOutput::Stdout,
+                &streaming_settings,
+                output_sink,
+            ),
+            ChildOutput::Stderr(bytes) => stderr_state.tee(
+                stderr,
+                bytes,
+                ChildOutput::Stderr,
+                &streaming_settings,
+                output_sink,
+            ),
             ChildOutput::Exit(code) => exit_code = code.0,
         };
     }
@@ -215,14 +360,30 @@ impl super::CommandRunner for CommandRunner {
                 // Update env, replacing `{chroot}` placeholders with `workdir_path`.
                 apply_chroot(workdir.path().to_str().unwrap(), &mut req);
 
-                // Prepare the workdir.
+                // Prepare the workdir, materializing its digest via the sandboxer if one is
+                // configured, or directly via the Store otherwise.
+                let sandboxer_materializer;
+                let store_materializer;
+                let materializer: &dyn Materializer = match self.sandboxer.as_ref() {
+                    Some(sandboxer) => {
+                        sandboxer_materializer = SandboxerMaterializer {
+                            sandboxer,
+                            store: &self.store,
+                        };
+                        &sandboxer_materializer
+                    }
+                    None => {
+                        store_materializer = StoreMaterializer { store: &self.store };
+                        &store_materializer
+                    }
+                };
                 let exclusive_spawn = prepare_workdir(
                     workdir.path().to_owned(),
                     &self.work_dir_base,
                     &req,
                     req.input_digests.inputs.clone(),
                     &self.store,
-                    self.sandboxer.as_ref(),
+                    materializer,
                     &self.named_caches,
                     &self.immutable_inputs,
                     None,
@@ -271,6 +432,7 @@ impl super::CommandRunner for CommandRunner {
                         &req.working_directory,
                         &req.argv,
                         workdir.path(),
+                        Some(&req.input_digests.inputs),
                     )?;
                 }
 
@@ -309,11 +471,88 @@ impl CapturedWorkdir for CommandRunner {
             // to stop automatic PATH searching.
             .env("PATH", "")
             .args(&req.argv[1..])
-            .current_dir(cwd)
+            .current_dir(&cwd)
             .envs(&req.env)
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            // Make the child the leader of its own process group (pgid == pid), so that any
+            // grandchildren it spawns can be torn down as a unit on timeout or cancellation,
+            // rather than surviving to corrupt the sandbox during cleanup.
+            .process_group(0);
+
+        // If this Process has opted into namespace-based hermetic isolation and the host actually
+        // supports it, confine the child to the prepared workdir (mount namespace + pivot_root),
+        // give it its own PID 1 (PID namespace), and optionally cut off networking (network
+        // namespace) -- falling back to a plain spawn otherwise.
+        if let Some(sandbox) = req
+            .execution_environment
+            // NB: requires upstream field -- `ExecutionEnvironment::namespace_sandbox`.
+            .namespace_sandbox
+            .filter(|s| !s.is_noop())
+        {
+            if self.supports_namespace_sandbox
+                && (if sandbox.rootless {
+                    rootless_namespace_sandbox_available()
+                } else {
+                    self.supports_privileged_namespace_sandbox
+                })
+            {
+                let mut bind_mount_sources = vec![
+                    self.immutable_inputs.workdir().to_owned(),
+                    self.named_caches.base_path().to_owned(),
+                ];
+                if let Some(jdk_home) = &req.jdk_home {
+                    bind_mount_sources.push(jdk_home.clone());
+                }
+                // `prepare_namespace_sandbox` does blocking filesystem I/O (stat-ing bind mount
+                // sources, creating their mount-point directories): push it onto a blocking-pool
+                // thread rather than stalling this tokio worker on it.
+                let workdir_path_owned = workdir_path.to_owned();
+                let cwd_for_prepare = cwd.clone();
+                let prepared = tokio::task::spawn_blocking(move || {
+                    prepare_namespace_sandbox(
+                        sandbox,
+                        &workdir_path_owned,
+                        &bind_mount_sources,
+                        &cwd_for_prepare,
+                    )
+                })
+                .await
+                .map_err(|e| format!("Namespace sandbox preparation task panicked: {e}"))?
+                .map_err(|e| format!("Error preparing namespace sandbox: {e}"))?;
+                unsafe {
+                    command.pre_exec(move || enter_namespace_sandbox_raw(&prepared));
+                }
+            } else {
+                debug!(
+                    "Namespace sandbox requested for {:?} but unavailable on this host \
+                     (likely missing user namespace support or privileges); falling back to a \
+                     plain spawn.",
+                    req
+                );
+            }
+        }
+
+        // If a jobserver is configured and this Process has opted in, consume a token from the
+        // shared pool before spawning (so that Pants' own concurrently-running local processes are
+        // bounded by the same budget as nested tools), and advertise a private lease on that token
+        // to the child so that tools speaking the GNU Make jobserver protocol coordinate their own
+        // parallelism through it instead of oversubscribing the machine -- see `JobserverLease` for
+        // why this goes through a per-child private pipe rather than the pool's own shared fds.
+        let jobserver_lease = match &self.jobserver {
+            // NB: requires upstream field -- `ExecutionEnvironment::use_jobserver`.
+            Some(jobserver) if req.execution_environment.use_jobserver => {
+                let lease = JobserverLease::acquire(jobserver.clone()).await?;
+                command.envs(lease.env_for_child());
+                let fds = lease.fds();
+                unsafe {
+                    command.pre_exec(move || clear_cloexec(fds).map_err(Into::into));
+                }
+                Some(lease)
+            }
+            _ => None,
+        };
 
         let mut child = spawn_process(self.spawn_lock.clone(), exclusive_spawn, move || {
             ManagedChild::spawn(&mut command, None)
@@ -321,6 +560,13 @@ impl CapturedWorkdir for CommandRunner {
         .await?;
 
         debug!("spawned local process as {:?} for {:?}", child.id(), req);
+        let pgid = child
+            .id()
+            .expect("Child should still be running immediately after being spawned.") as i32;
+        // NB: requires upstream field -- `ExecutionEnvironment::shutdown_style`.
+        let shutdown_style = req.execution_environment.shutdown_style.unwrap_or_default();
+        let group_guard = ProcessGroupGuard::new(pgid, shutdown_style);
+
         let stdout_stream = FramedRead::new(child.stdout.take().unwrap(), BytesCodec::new())
             .map_ok(|bytes| ChildOutput::Stdout(bytes.into()))
             .fuse()
@@ -330,17 +576,27 @@ impl CapturedWorkdir for CommandRunner {
             .fuse()
             .boxed();
         let exit_stream = async move {
-            child
-                .wait()
-                .map_ok(|exit_status| {
-                    ChildOutput::Exit(ExitCode(
-                        exit_status
-                            .code()
-                            .or_else(|| exit_status.signal().map(Neg::neg))
-                            .expect("Child process should exit via returned code or signal."),
-                    ))
-                })
-                .await
+            // NB: `jobserver_lease` is moved in only to be dropped alongside this future. Locals
+            // drop in reverse declaration order, so `_group_guard` below (declared after this one)
+            // drops -- and finishes tearing down the process group -- before this lease releases
+            // its token back to the shared pool, however this future ends.
+            let _jobserver_lease = jobserver_lease;
+            // NB: `group_guard` is held for the lifetime of this future (and dropped alongside
+            // it, whether that's because `child.wait()` below resolved or because the combined
+            // stream was dropped early on timeout or cancellation): its `Drop` impl confirms the
+            // whole process group -- not just the leader `child.wait()` reaps -- has exited,
+            // escalating from SIGTERM to SIGKILL if needed, so that the "has completely exited
+            // when the BoxStream is Dropped" invariant holds for subprocess trees too.
+            let _group_guard = group_guard;
+            let wait_result = child.wait().await;
+            wait_result.map(|exit_status| {
+                ChildOutput::Exit(ExitCode(
+                    exit_status
+                        .code()
+                        .or_else(|| exit_status.signal().map(Neg::neg))
+                        .expect("Child process should exit via returned code or signal."),
+                ))
+            })
         }
         .into_stream()
         .boxed();
@@ -353,6 +609,355 @@ impl CapturedWorkdir for CommandRunner {
     }
 }
 
+/// Implements (the anonymous-pipe form of) the GNU Make jobserver protocol, so that nested build
+/// tools invoked by Pants (`make`, `cargo`, `ninja`, `bazel`, compilers, ...) share Pants' own
+/// concurrency budget instead of each spinning up an independent worker pool and oversubscribing
+/// the machine. Tokens are single bytes in a pipe: a holder `read()`s one to claim a parallel job
+/// and `write()`s it back when that job finishes; one implicit token (not represented in the
+/// pipe) is always available to whoever already holds the jobserver.
+pub struct Jobserver {
+    read_fd: std::os::unix::io::RawFd,
+    write_fd: std::os::unix::io::RawFd,
+    advertise: bool,
+    capacity: usize,
+}
+
+/// The process-wide jobserver, shared by every `CommandRunner` (and in turn every concurrently
+/// running local process, whether spawned by Pants directly or nested inside one of those
+/// processes) so that they all draw down the same token pool rather than each `CommandRunner`
+/// maintaining its own, which would allow the budget to be oversubscribed by a factor of however
+/// many `CommandRunner`s happen to be live.
+static GLOBAL_JOBSERVER: std::sync::OnceLock<Arc<Jobserver>> = std::sync::OnceLock::new();
+
+impl Jobserver {
+    /// Creates a jobserver whose pool holds `capacity` tokens (i.e. up to `capacity` jobs,
+    /// Pants-spawned or nested, may run at once). If `advertise` is false, the pool is still used
+    /// to bound Pants' own local concurrency, but is not exposed to children.
+    pub fn new(capacity: usize, advertise: bool) -> Result<Jobserver, String> {
+        let mut fds = [0 as std::os::unix::io::RawFd; 2];
+        // `O_CLOEXEC` so these fds are closed-on-exec by default: without it, they'd survive into
+        // every process this one forks+execs, jobserver-opted-in or not (including
+        // namespace-sandboxed "hermetic" children), defeating whatever isolation those children
+        // are supposed to have. `run_in_workdir` selectively clears the flag again (via
+        // `clear_cloexec` on a `JobserverLease`'s private pipe), but only in the one child that's
+        // actually meant to inherit it.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(format!(
+                "Failed to create jobserver pipe: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let [read_fd, write_fd] = fds;
+
+        let tokens = vec![b'+'; capacity];
+        let written =
+            unsafe { libc::write(write_fd, tokens.as_ptr() as *const libc::c_void, tokens.len()) };
+        if written != capacity as isize {
+            return Err(format!(
+                "Failed to pre-load {capacity} jobserver tokens: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(Jobserver {
+            read_fd,
+            write_fd,
+            advertise,
+            capacity,
+        })
+    }
+
+    /// Returns the process-global jobserver, creating it (sized to `capacity`, and advertising
+    /// itself to children iff `advertise`) on the first call. Later calls ignore their arguments
+    /// and return the same instance: the pool is sized once, by whichever `CommandRunner` is
+    /// constructed first.
+    pub fn global(capacity: usize, advertise: bool) -> Result<Arc<Jobserver>, String> {
+        if let Some(jobserver) = GLOBAL_JOBSERVER.get() {
+            return Ok(jobserver.clone());
+        }
+        let jobserver = Arc::new(Jobserver::new(capacity, advertise)?);
+        Ok(GLOBAL_JOBSERVER.get_or_init(|| jobserver).clone())
+    }
+
+    /// Blocks until a token is available, consuming it. Pairs with `release`.
+    ///
+    /// Runs the blocking `read` on a `spawn_blocking` thread rather than inline on the calling
+    /// async task: every locally-executed process acquires here, so under saturation every tokio
+    /// worker could otherwise end up parked in this `read`, and since a token is only freed when
+    /// some other in-flight process completes -- which itself needs a worker to make progress --
+    /// that would stall or deadlock the runtime.
+    async fn acquire(self: Arc<Self>) -> Result<(), String> {
+        tokio::task::spawn_blocking(move || {
+            let mut byte: u8 = 0;
+            let res =
+                unsafe { libc::read(self.read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if res != 1 {
+                return Err(format!(
+                    "Failed to acquire jobserver token: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| format!("Jobserver token acquisition task panicked: {e}"))?
+    }
+
+    /// Returns a single token to the pool.
+    fn release(&self) {
+        let byte: u8 = b'+';
+        unsafe {
+            libc::write(self.write_fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+
+    /// The `MAKEFLAGS`/`CARGO_MAKEFLAGS` assignments that advertise this jobserver's pipe to a
+    /// spawned child, so that tools which speak the protocol draw from the same token pool.
+    fn env_for_child(&self) -> Vec<(String, String)> {
+        if !self.advertise {
+            return vec![];
+        }
+        let auth = format!("{},{}", self.read_fd, self.write_fd);
+        vec![
+            ("MAKEFLAGS".to_owned(), format!("--jobserver-auth={auth}")),
+            (
+                "CARGO_MAKEFLAGS".to_owned(),
+                format!("--jobserver-auth={auth}"),
+            ),
+        ]
+    }
+
+    /// Clears `FD_CLOEXEC` on the pipe's fds in the (post-fork, pre-exec) child, so that they
+    /// survive into the spawned process: the fd numbers advertised above are only meaningful to a
+    /// process that has actually inherited these descriptors.
+    fn inherit_fds(&self) -> std::io::Result<()> {
+        clear_cloexec([self.read_fd, self.write_fd])
+    }
+
+    /// The raw fd pair backing this pipe, for callers (namely `JobserverLease`) that need to reach
+    /// them from a `pre_exec` closure without keeping a whole `Jobserver` borrowed alive for it.
+    fn fds(&self) -> [std::os::unix::io::RawFd; 2] {
+        [self.read_fd, self.write_fd]
+    }
+}
+
+/// As `Jobserver::inherit_fds`, but operating on a raw fd pair rather than through a `Jobserver`
+/// reference, so it can be called from a `pre_exec` closure that only captured plain (`Copy`) fd
+/// numbers.
+fn clear_cloexec(fds: [std::os::unix::io::RawFd; 2]) -> std::io::Result<()> {
+    for fd in fds {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read_fd);
+            libc::close(self.write_fd);
+        }
+    }
+}
+
+/// Lends a spawned process (and anything it forks) exactly one token out of a parent `Jobserver`
+/// pool: that token is the child's own implicit jobserver token, so the private pipe advertised to
+/// it in place of the parent pool's own shared fds starts out empty.
+///
+/// This exists because reclaiming an orphaned token from the *shared* pool is unsound: with many
+/// processes concurrently reading and writing the same pipe, the number of tokens currently
+/// sitting in it is never a reliable signal of how many (if any) a specific exited process
+/// orphaned -- a token "missing" from the pipe could just as well be checked out by some other
+/// still-running process as by a dead one. A private, single-child pipe has no such ambiguity: no
+/// other concurrently-running process can ever read or write it, so once this process' entire
+/// group is confirmed exited (the point at which this lease is dropped, alongside the
+/// `ProcessGroupGuard` for the same child), the one token lent to it is safe to return to the
+/// parent pool unconditionally: nothing alive could still be holding it.
+struct JobserverLease {
+    /// The private pipe advertised to the child in place of `parent`'s own fds.
+    sub: Jobserver,
+    /// The pool this lease's one token was drawn from, and is returned to on drop.
+    parent: Arc<Jobserver>,
+}
+
+impl JobserverLease {
+    /// Acquires one token from `parent` (to serve as the child's implicit token) and mints an
+    /// empty private pipe to advertise to a single spawned child.
+    async fn acquire(parent: Arc<Jobserver>) -> Result<Self, String> {
+        parent.clone().acquire().await?;
+        let sub = Jobserver::new(0, parent.advertise)?;
+        Ok(Self { sub, parent })
+    }
+
+    fn env_for_child(&self) -> Vec<(String, String)> {
+        self.sub.env_for_child()
+    }
+
+    fn fds(&self) -> [std::os::unix::io::RawFd; 2] {
+        self.sub.fds()
+    }
+}
+
+impl Drop for JobserverLease {
+    fn drop(&mut self) {
+        // The whole subtree that could have been holding the lent token is confirmed gone by this
+        // point (see the struct doc comment), so it's always safe to return exactly the one token
+        // this lease drew from `parent`.
+        self.parent.release();
+    }
+}
+
+/// Governs how a process group is torn down on timeout or cancellation, mirroring turborepo's
+/// `ShutdownStyle`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownStyle {
+    /// Send SIGTERM to the process group, and give it `grace_period` to exit on its own before
+    /// escalating to SIGKILL.
+    Graceful { grace_period: std::time::Duration },
+    /// Send SIGKILL to the process group immediately.
+    Immediate,
+}
+
+impl Default for ShutdownStyle {
+    fn default() -> Self {
+        Self::Graceful {
+            grace_period: std::time::Duration::from_secs(3),
+        }
+    }
+}
+
+/// Ensures that a spawned child's entire process group has been signaled down to (at worst)
+/// SIGKILL by the time this guard is dropped, escalating from SIGTERM according to
+/// `shutdown_style` if any members are still alive, and gives the group a bounded window to
+/// actually disappear afterward. This covers both the timeout/cancellation case (the leader itself
+/// may still be running) and the case where the leader has already exited but left other group
+/// members behind (e.g. a backgrounded job it never waited on).
+///
+/// NB: this is a best-effort, bounded wait, not an unconditional guarantee. `waitpid` can only
+/// reap this process' own children; a re-parented grandchild (no longer a child of ours once its
+/// immediate parent exits) is invisible to it and still shows up alive via `kill(-pgid, 0)`
+/// indefinitely. SIGKILL can't be caught or blocked, so in the overwhelmingly common case the
+/// group disappears almost immediately regardless, but a member stuck in uninterruptible (D-state)
+/// sleep, or such an unreapable grandchild, can outlive this guard's wait. Callers (in turn the
+/// sandbox/workdir cleanup that follows) should treat the group as torn down for all practical
+/// purposes once this guard is gone, not as a hard guarantee that zero processes from it remain.
+struct ProcessGroupGuard {
+    pgid: i32,
+    shutdown_style: ShutdownStyle,
+}
+
+impl ProcessGroupGuard {
+    fn new(pgid: i32, shutdown_style: ShutdownStyle) -> Self {
+        Self {
+            pgid,
+            shutdown_style,
+        }
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if !process_group_exists(self.pgid) {
+            // The group's leader is the only member that matters in the overwhelmingly common
+            // case, and `child.wait()` (awaited just before this guard is dropped on the normal
+            // path) already reaped it; nothing further to do unless it left other members behind.
+            return;
+        }
+
+        let pgid = self.pgid;
+        let shutdown_style = self.shutdown_style;
+        // NB: The grace-period wait below is a synchronous busy-wait, which would otherwise block
+        // whichever tokio worker is dropping this guard (e.g. on timeout or cancellation) for up
+        // to the full grace period. `block_in_place` hands this worker's other queued tasks off to
+        // the runtime's other workers for the duration, while still letting us block here until
+        // the group is confirmed torn down -- which callers (in turn the sandbox cleanup that
+        // follows) rely on. `block_in_place` itself panics outside a multi-threaded runtime, so
+        // fall back to running inline when there's no such runtime whose other tasks we'd need to
+        // hand off anyway (e.g. a current-thread runtime, or no runtime at all).
+        let on_multi_thread_runtime = tokio::runtime::Handle::try_current()
+            .is_ok_and(|handle| handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread);
+        if on_multi_thread_runtime {
+            tokio::task::block_in_place(move || teardown_process_group(pgid, shutdown_style));
+        } else {
+            teardown_process_group(pgid, shutdown_style);
+        }
+    }
+}
+
+/// How long to give the group to actually disappear after SIGKILL before giving up on it. SIGKILL
+/// can't be caught, blocked, or ignored, so a group that's reapable by us at all should vanish
+/// within well under this; it exists purely as a bound against looping forever on a D-state member
+/// or a re-parented grandchild `waitpid` can never reap (see `ProcessGroupGuard`'s NB).
+const SIGKILL_REAP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Signals the process group led by `pgid` per `shutdown_style`, blocking the calling thread for a
+/// bounded time while it disappears.
+fn teardown_process_group(pgid: i32, shutdown_style: ShutdownStyle) {
+    let grace_period = match shutdown_style {
+        ShutdownStyle::Immediate => std::time::Duration::ZERO,
+        ShutdownStyle::Graceful { grace_period } => grace_period,
+    };
+    if grace_period > std::time::Duration::ZERO {
+        signal_process_group(pgid, libc::SIGTERM);
+        wait_for_process_group_exit(pgid, grace_period);
+    }
+    if process_group_exists(pgid) {
+        signal_process_group(pgid, libc::SIGKILL);
+        // A single WNOHANG reap pass right after signaling isn't enough to know the group is
+        // gone: members can still be a few scheduler ticks from actually dying, and re-parented
+        // grandchildren (no longer our children, so `waitpid` returns ECHILD for them) are never
+        // reaped by us at all even though `kill(-pgid, 0)` still sees them. Poll
+        // `process_group_exists` -- which covers both cases -- for a bounded window instead of
+        // trusting one pass.
+        wait_for_process_group_exit(pgid, SIGKILL_REAP_TIMEOUT);
+    }
+    // Reap whatever of our own children are immediately available; this can't do anything about
+    // re-parented grandchildren that outlived the wait above, if any did.
+    reap_process_group(pgid);
+}
+
+/// Repeatedly reaps and polls the group led by `pgid` until `kill(-pgid, 0)` reports no member
+/// alive, or `timeout` elapses, whichever comes first.
+fn wait_for_process_group_exit(pgid: i32, timeout: std::time::Duration) {
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        reap_process_group(pgid);
+        if !process_group_exists(pgid) || std::time::Instant::now() >= deadline {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+/// Sends `signal` to every process in the group led by `pgid` (a negative pid targets the whole
+/// group, per `kill(2)`).
+fn signal_process_group(pgid: i32, signal: i32) {
+    unsafe {
+        libc::kill(-pgid, signal);
+    }
+}
+
+/// Whether any process in the group led by `pgid` is still alive.
+fn process_group_exists(pgid: i32) -> bool {
+    unsafe { libc::kill(-pgid, 0) == 0 || *libc::__errno_location() != libc::ESRCH }
+}
+
+/// Reaps whichever members of the group led by `pgid` are our own (still-)direct children and
+/// have already exited, so they aren't left as zombies. Re-parented grandchildren are no longer
+/// ours to wait() on and aren't touched here even if alive or already exited elsewhere.
+fn reap_process_group(pgid: i32) {
+    loop {
+        let mut status = 0;
+        let res = unsafe { libc::waitpid(-pgid, &mut status, libc::WNOHANG) };
+        if res <= 0 {
+            return;
+        }
+    }
+}
+
 /// Variations of errors that can occur when setting up the work directory for process execution.
 #[derive(Debug)]
 pub enum CapturedWorkdirError {
@@ -414,10 +1019,18 @@ pub trait CapturedWorkdir {
         let mut stdout = BytesMut::with_capacity(8192);
         let mut stderr = BytesMut::with_capacity(8192);
 
-        // Spawn the process.
-        // NB: We fully buffer the `Stream` into the stdout/stderr buffers, but the idea going forward
-        // is that we eventually want to pass incremental results on down the line for streaming
-        // process results to console logs, etc.
+        // Spawn the process, tee-ing its output into the stdout/stderr buffers for the final
+        // capture and, once a stream has been buffering for long enough, into the Context's
+        // output sink for live console logs. A Process that has opted into live streaming
+        // outright skips the buffering stage entirely, to avoid hanging or long-running tools
+        // going silent until they exit.
+        // NB: requires upstream fields -- `ExecutionEnvironment::enable_output_streaming` and
+        // `ExecutionEnvironment::output_streaming_settings`.
+        let streaming_settings = if req.execution_environment.enable_output_streaming {
+            OutputStreamingSettings::immediate()
+        } else {
+            req.execution_environment.output_streaming_settings
+        };
         let exit_code_result = {
             let workdir_token = workdir_token.clone();
             let exit_code_future = collect_child_outputs(
@@ -431,6 +1044,9 @@ pub trait CapturedWorkdir {
                     exclusive_spawn,
                 )
                 .await?,
+                streaming_settings,
+                // NB: requires upstream field -- `Context::output_sink`.
+                context.output_sink.as_deref(),
             );
 
             if let Some(req_timeout) = req.timeout {
@@ -552,6 +1168,426 @@ pub trait CapturedWorkdir {
     }
 }
 
+/// Which Linux namespaces to isolate a locally-executed process into, giving it a confined view
+/// of the filesystem (only the prepared workdir plus declared immutable inputs, named caches, and
+/// the JDK, all but the workdir itself read-only), its own PID 1, and, optionally, no network.
+/// This is a strictly stronger, opt-in alternative to the `{chroot}` string substitution that
+/// `apply_chroot` performs: that one only rewrites paths the child is told about, while this
+/// actually prevents it from reading or writing anything else.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceSandbox {
+    /// Pivot into a new mount namespace rooted at the prepared workdir.
+    pub mount: bool,
+    /// Run as PID 1 of a new PID namespace, so that grandchildren are cleanly reaped.
+    pub pid: bool,
+    /// Use a new network namespace with no interfaces configured, i.e. no network access.
+    pub network: bool,
+    /// Pair `mount` (and optionally `pid`/`network`) with a new user namespace mapping the
+    /// caller's uid/gid to root inside the namespace, so that the sandbox can be entered by an
+    /// unprivileged user (no `CAP_SYS_ADMIN` on the host required), analogous to a PVF worker
+    /// jail. Requires `mount` to be set.
+    pub rootless: bool,
+}
+
+impl NamespaceSandbox {
+    fn is_noop(&self) -> bool {
+        !self.mount && !self.pid && !self.network
+    }
+}
+
+/// Whether this host plausibly supports the namespace operations `NamespaceSandbox` relies on.
+/// This is a coarse, cheap check performed once at `CommandRunner` construction; actually
+/// entering the sandbox can still fail for a specific process (e.g. a locked-down kernel that
+/// disallows unprivileged `unshare`), in which case `enter_namespace_sandbox_raw` surfaces that as a
+/// spawn error rather than silently running unconfined.
+fn namespace_sandbox_available() -> bool {
+    Path::new("/proc/self/ns/mnt").exists()
+}
+
+/// Whether this host allows an unprivileged process to create its own user namespace, which
+/// `NamespaceSandbox::rootless` requires. Some distributions disable this via sysctl even when
+/// `/proc/self/ns/user` exists.
+fn rootless_namespace_sandbox_available() -> bool {
+    std::fs::read_to_string("/proc/sys/kernel/unprivileged_userns_clone")
+        .map(|contents| contents.trim() == "1")
+        .unwrap_or(true)
+}
+
+/// Whether this process has the privilege a non-`rootless` namespace sandbox needs: creating
+/// mount/PID namespaces without first mapping into a user namespace requires `CAP_SYS_ADMIN`,
+/// which in practice means running as root. Checked once at `CommandRunner` construction, like
+/// `namespace_sandbox_available` -- this catches the predictable, common case of an unprivileged
+/// caller requesting a non-`rootless` sandbox so it can fall back to a plain spawn instead of
+/// reaching a hard `unshare(EPERM)` inside the forked child's `pre_exec` (where failure aborts the
+/// whole spawn). A deeper, less predictable kernel-specific failure (e.g. a locked-down kernel that
+/// also disallows privileged namespace operations) is still surfaced by
+/// `enter_namespace_sandbox_raw` as a spawn error rather than silently running unconfined.
+fn privileged_namespace_sandbox_available() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Upper bound on the number of bind mounts a namespace sandbox sets up (currently: the immutable
+/// inputs dir, the named caches dir, and an optional JDK home). Fixed so that
+/// `PreparedNamespaceSandbox` can hold them in a plain array rather than a `Vec`: see `FixedBuf`'s
+/// doc comment for why nothing reachable from `pre_exec` may be backed by the heap.
+const MAX_BIND_MOUNTS: usize = 8;
+
+const RAW_PATH_CAPACITY: usize = 4096;
+const RAW_ID_MAP_CAPACITY: usize = 32;
+
+/// A NUL-free byte buffer of fixed capacity `N`, built ahead of time -- in ordinary,
+/// allocation-safe code, before `fork` -- so that `enter_namespace_sandbox_raw` (which runs
+/// single-threaded in a child just forked from what may be a multithreaded parent, where another
+/// thread could have been holding the allocator's lock at the moment of `fork`) never needs to
+/// `malloc`/`free` to do its work: it only ever reads already-initialized, plain `Copy` memory and
+/// issues raw syscalls.
+#[derive(Clone, Copy)]
+struct FixedBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for FixedBuf<N> {
+    fn default() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+}
+
+impl<const N: usize> FixedBuf<N> {
+    fn new(bytes: &[u8]) -> std::io::Result<Self> {
+        if bytes.len() + 1 > N || bytes.contains(&0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "value too long, or contains an embedded NUL, for a fixed-capacity buffer",
+            ));
+        }
+        let mut buf = [0u8; N];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(Self {
+            buf,
+            len: bytes.len(),
+        })
+    }
+
+    /// A pointer to the buffer's NUL-terminated contents, suitable for passing directly to a libc
+    /// function expecting a `const char *`.
+    fn as_ptr(&self) -> *const libc::c_char {
+        self.buf.as_ptr() as *const libc::c_char
+    }
+
+    /// The buffer's valid (non-padding) contents, excluding the terminating NUL.
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+type RawPath = FixedBuf<RAW_PATH_CAPACITY>;
+
+impl RawPath {
+    fn from_path(path: &Path) -> std::io::Result<Self> {
+        Self::new(path.as_os_str().as_encoded_bytes())
+    }
+}
+
+/// Everything `enter_namespace_sandbox_raw` needs, computed by `prepare_namespace_sandbox` before
+/// `fork`. A plain `Copy` struct with no heap-backed fields (see `FixedBuf`), so that moving it
+/// into the `pre_exec` closure -- and whatever happens to it on an error path before `exec` --
+/// never allocates or deallocates.
+#[derive(Clone, Copy)]
+struct PreparedNamespaceSandbox {
+    sandbox: NamespaceSandbox,
+    root: RawPath,
+    workdir: RawPath,
+    old_root: RawPath,
+    detached_old_root: RawPath,
+    cwd: RawPath,
+    uid_map: FixedBuf<RAW_ID_MAP_CAPACITY>,
+    gid_map: FixedBuf<RAW_ID_MAP_CAPACITY>,
+    bind_mounts: [Option<(RawPath, RawPath)>; MAX_BIND_MOUNTS],
+    bind_mount_count: usize,
+}
+
+/// Precomputes everything `enter_namespace_sandbox_raw` needs to confine a spawned process
+/// according to `sandbox`: resolved paths, pre-created bind-mount target and pivot-root scratch
+/// directories, and (if rootless) the formatted uid/gid map content. Runs in ordinary code before
+/// `fork`/`pre_exec`, so it is free to allocate, touch the filesystem, and fail with a normal
+/// descriptive error.
+fn prepare_namespace_sandbox(
+    sandbox: NamespaceSandbox,
+    workdir_path: &Path,
+    bind_mount_sources: &[PathBuf],
+    cwd: &Path,
+) -> std::io::Result<PreparedNamespaceSandbox> {
+    let mut bind_mounts = [None; MAX_BIND_MOUNTS];
+    let mut bind_mount_count = 0;
+    if sandbox.mount {
+        for source in bind_mount_sources {
+            if !source.exists() {
+                continue;
+            }
+            if bind_mount_count >= MAX_BIND_MOUNTS {
+                return Err(std::io::Error::other(format!(
+                    "Namespace sandbox only supports up to {MAX_BIND_MOUNTS} bind mounts"
+                )));
+            }
+            let target = workdir_path.join(source.strip_prefix("/").unwrap_or(source));
+            std::fs::create_dir_all(&target)?;
+            bind_mounts[bind_mount_count] =
+                Some((RawPath::from_path(source)?, RawPath::from_path(&target)?));
+            bind_mount_count += 1;
+        }
+    }
+
+    let old_root = workdir_path.join(".pants-old-root");
+    if sandbox.mount {
+        std::fs::create_dir_all(&old_root)?;
+    }
+
+    let (uid_map, gid_map) = if sandbox.rootless {
+        let uid = unsafe { libc::getuid() };
+        let gid = unsafe { libc::getgid() };
+        (
+            FixedBuf::new(format!("0 {uid} 1\n").as_bytes())?,
+            FixedBuf::new(format!("0 {gid} 1\n").as_bytes())?,
+        )
+    } else {
+        (FixedBuf::default(), FixedBuf::default())
+    };
+
+    Ok(PreparedNamespaceSandbox {
+        sandbox,
+        root: RawPath::from_path(Path::new("/"))?,
+        workdir: RawPath::from_path(workdir_path)?,
+        old_root: RawPath::from_path(&old_root)?,
+        detached_old_root: RawPath::from_path(Path::new("/.pants-old-root"))?,
+        // After `pivot_root`, the workdir is `/`, so the cwd must be re-expressed relative to it.
+        cwd: if sandbox.mount {
+            RawPath::from_path(&Path::new("/").join(cwd.strip_prefix(workdir_path).unwrap_or(cwd)))?
+        } else {
+            RawPath::from_path(cwd)?
+        },
+        uid_map,
+        gid_map,
+        bind_mounts,
+        bind_mount_count,
+    })
+}
+
+fn mount_raw(source: Option<&RawPath>, target: &RawPath, flags: libc::c_ulong) -> std::io::Result<()> {
+    let res = unsafe {
+        libc::mount(
+            source.map(RawPath::as_ptr).unwrap_or(std::ptr::null()),
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Recursively remounts `path` as `MS_PRIVATE`, so that mount/unmount events inside the new mount
+/// namespace don't propagate back out to the real root (and vice versa).
+fn remount_private_raw(path: &RawPath) -> std::io::Result<()> {
+    mount_raw(None, path, libc::MS_PRIVATE | libc::MS_REC)
+}
+
+/// Bind-mounts a previously-validated `(source, target)` pair read-only. `target`'s parent
+/// directories must already exist: created by `prepare_namespace_sandbox`, since this cannot
+/// allocate to do so itself.
+fn bind_mount_readonly_raw(mount: &(RawPath, RawPath)) -> std::io::Result<()> {
+    let (source, target) = mount;
+    mount_raw(Some(source), target, libc::MS_BIND | libc::MS_REC)?;
+    mount_raw(
+        None,
+        target,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_REC,
+    )
+}
+
+fn chdir_raw(path: &RawPath) -> std::io::Result<()> {
+    if unsafe { libc::chdir(path.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `pivot_root`s into `workdir`, detaching the old root (relocated to `old_root`, visible from the
+/// new root as `detached_old_root`) once the new one is in place.
+///
+/// Invariants upheld here: the new root is bind-mounted onto itself first, since `pivot_root`
+/// requires its target to already be a mount point; and the old root is unmounted (`MNT_DETACH`)
+/// before returning, so that none of the original host filesystem remains reachable. Unlike the
+/// original, allocating implementation, the now-empty `old_root` mount point is not `rmdir`'d
+/// afterwards: it is unreachable dead weight inside a namespace that is torn down in full when
+/// this process exits, not meaningfully leaked, and removing it would require an allocation this
+/// function cannot perform.
+fn pivot_into_raw(
+    workdir: &RawPath,
+    old_root: &RawPath,
+    root: &RawPath,
+    detached_old_root: &RawPath,
+) -> std::io::Result<()> {
+    mount_raw(Some(workdir), workdir, libc::MS_BIND)?;
+
+    let res = unsafe { libc::syscall(libc::SYS_pivot_root, workdir.as_ptr(), old_root.as_ptr()) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    chdir_raw(root)?;
+    if unsafe { libc::umount2(detached_old_root.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Maps the calling process' uid/gid to root (0) inside a freshly-`unshare`d user namespace, by
+/// writing `/proc/self/{uid,gid}_map`. Must be called by the process that created the namespace,
+/// after `unshare(CLONE_NEWUSER)` but before dropping privileges further (e.g. via `pivot_root`),
+/// since writing `gid_map` requires first denying `setgroups`. `uid_map`/`gid_map` must already
+/// hold the fully-formatted map content: this issues only raw `open`/`write`/`close` syscalls.
+fn map_rootless_ids_raw(
+    uid_map: &FixedBuf<RAW_ID_MAP_CAPACITY>,
+    gid_map: &FixedBuf<RAW_ID_MAP_CAPACITY>,
+) -> std::io::Result<()> {
+    write_proc_self_raw(b"/proc/self/setgroups\0", b"deny")?;
+    write_proc_self_raw(b"/proc/self/uid_map\0", uid_map.as_bytes())?;
+    write_proc_self_raw(b"/proc/self/gid_map\0", gid_map.as_bytes())
+}
+
+/// Opens the (already NUL-terminated) `path`, writes `content` to it, and closes it -- raw
+/// syscalls over caller-owned buffers only, no allocation.
+fn write_proc_self_raw(path: &[u8], content: &[u8]) -> std::io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = libc::write(fd, content.as_ptr() as *const libc::c_void, content.len());
+        libc::close(fd);
+        if written != content.len() as isize {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the grandchild that became PID 1 of the new PID namespace (see
+/// `enter_namespace_sandbox_raw`), then exits mirroring its exit status -- or the signal that
+/// killed it -- so that Pants, which only ever observes this process (the direct child of
+/// `Command::spawn`), sees the same outcome it would have if that grandchild had been `exec`'d
+/// directly.
+fn wait_and_mirror_exit(child_pid: libc::pid_t) -> ! {
+    let mut status: libc::c_int = 0;
+    loop {
+        let res = unsafe { libc::waitpid(child_pid, &mut status, 0) };
+        if res == -1 && unsafe { *libc::__errno_location() } == libc::EINTR {
+            continue;
+        }
+        break;
+    }
+    unsafe {
+        if libc::WIFSIGNALED(status) {
+            let sig = libc::WTERMSIG(status);
+            // Restore the default disposition and make sure `sig` isn't blocked in our own mask
+            // (e.g. inherited from whatever spawned this process) before raising it below --
+            // otherwise `raise` would only leave it pending instead of actually terminating us,
+            // and we'd fall through to `_exit` with a WEXITSTATUS that's meaningless for a
+            // signal death.
+            libc::signal(sig, libc::SIG_DFL);
+            let mut unblock_set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut unblock_set);
+            libc::sigaddset(&mut unblock_set, sig);
+            libc::sigprocmask(libc::SIG_UNBLOCK, &unblock_set, std::ptr::null_mut());
+            libc::raise(sig);
+        }
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+}
+
+/// Unshares, and pivots/chdirs into, the remaining (non-PID) namespaces `prepared.sandbox` asks
+/// for, using only the paths `prepare_namespace_sandbox` already resolved.
+fn finish_namespace_sandbox_raw(prepared: &PreparedNamespaceSandbox) -> std::io::Result<()> {
+    let sandbox = prepared.sandbox;
+    let mut flags = 0;
+    if sandbox.mount {
+        flags |= libc::CLONE_NEWNS;
+    }
+    if sandbox.network {
+        flags |= libc::CLONE_NEWNET;
+    }
+    if flags != 0 && unsafe { libc::unshare(flags) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    if sandbox.mount {
+        remount_private_raw(&prepared.root)?;
+        for bind_mount in prepared.bind_mounts[..prepared.bind_mount_count].iter().flatten() {
+            bind_mount_readonly_raw(bind_mount)?;
+        }
+        pivot_into_raw(
+            &prepared.workdir,
+            &prepared.old_root,
+            &prepared.root,
+            &prepared.detached_old_root,
+        )?;
+    }
+
+    chdir_raw(&prepared.cwd)
+}
+
+/// Runs between `fork` and `exec` (i.e. from a `pre_exec` hook) to confine the about-to-be-spawned
+/// process according to `prepared.sandbox`, using only raw syscalls over the plain, already-sized
+/// buffers `prepare_namespace_sandbox` built ahead of time (see `FixedBuf`'s doc comment for why:
+/// this runs single-threaded in a child just forked from what may be a multithreaded parent, where
+/// any `malloc`/`free` here could deadlock on another thread's held allocator lock).
+///
+/// `CLONE_NEWPID` only takes effect for children created *after* the call to `unshare`, not the
+/// calling process itself, so giving the spawned process its own PID 1 (and in turn clean reaping
+/// of its grandchildren) requires an explicit `fork` here: the fork's child becomes PID 1 of the
+/// new namespace and goes on to `exec` the real process (by returning `Ok(())`, letting the usual
+/// post-`pre_exec` `exec` proceed in it), while the fork's parent waits for that child and mirrors
+/// its exit status, so that Pants -- which only ever sees this function's caller, the direct child
+/// of `Command::spawn` -- observes the same outcome it would have gotten from a direct `exec`. This
+/// is the same trick `util-linux`'s `unshare --fork --pid` relies on.
+///
+/// # Safety
+/// Must only be called from a `pre_exec` closure: the child is single-threaded and has not yet
+/// called `exec`, which is the narrow window in which calling into libc like this is sound.
+unsafe fn enter_namespace_sandbox_raw(prepared: &PreparedNamespaceSandbox) -> std::io::Result<()> {
+    let sandbox = prepared.sandbox;
+    if !sandbox.pid {
+        if sandbox.rootless {
+            if libc::unshare(libc::CLONE_NEWUSER) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            map_rootless_ids_raw(&prepared.uid_map, &prepared.gid_map)?;
+        }
+        return finish_namespace_sandbox_raw(prepared);
+    }
+
+    let pre_fork_flags =
+        libc::CLONE_NEWPID | if sandbox.rootless { libc::CLONE_NEWUSER } else { 0 };
+    if libc::unshare(pre_fork_flags) != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if sandbox.rootless {
+        map_rootless_ids_raw(&prepared.uid_map, &prepared.gid_map)?;
+    }
+    match libc::fork() {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => finish_namespace_sandbox_raw(prepared),
+        child_pid => wait_and_mirror_exit(child_pid),
+    }
+}
+
 ///
 /// Mutates a Process, replacing any `{chroot}` placeholders with `chroot_path`.
 ///
@@ -661,6 +1697,98 @@ pub async fn prepare_workdir_digest(
         })
 }
 
+/// A pluggable backend for materializing a Process' complete input digest into its sandbox
+/// directory, selected at runtime by `prepare_workdir`'s caller (much like a DVCS' `Backend`
+/// trait lets it support multiple object-storage implementations behind one interface). The
+/// built-in implementations below, [`SandboxerMaterializer`] and [`StoreMaterializer`], are the
+/// two historically hardcoded paths: dispatching to an out-of-process sandboxer, or materializing
+/// directly via the `Store` in-process. A downstream consumer can implement this trait to plug in
+/// an alternative strategy instead -- e.g. a FUSE/overlay backend that lazily faults in digest
+/// contents on first access, or a reflink/hardlink-based copy-on-write backend for large immutable
+/// inputs -- without touching `prepare_workdir` itself.
+#[async_trait]
+pub trait Materializer: Send + Sync {
+    async fn materialize(
+        &self,
+        workdir_path: PathBuf,
+        workdir_root_path: &Path,
+        digest: DirectoryDigest,
+        mutable_paths: &BTreeSet<RelativePath>,
+    ) -> Result<(), String>;
+}
+
+/// Materializes by delegating to an out-of-process `sandboxer`, which materializes from its own
+/// store. Since the sandboxer only sees persisted digests, this first records the digest trie (if
+/// any) into the `Store`.
+pub struct SandboxerMaterializer<'a> {
+    pub sandboxer: &'a Sandboxer,
+    pub store: &'a Store,
+}
+
+#[async_trait]
+impl Materializer for SandboxerMaterializer<'_> {
+    async fn materialize(
+        &self,
+        workdir_path: PathBuf,
+        workdir_root_path: &Path,
+        digest: DirectoryDigest,
+        mutable_paths: &BTreeSet<RelativePath>,
+    ) -> Result<(), String> {
+        debug!("Materializing via sandboxer to {workdir_path:?}: {digest:#?}");
+        // Ensure that the tree is persisted in the store, so that the sandboxer can materialize it
+        // from there. Since record_digest_trie() takes ownership of its argument, and we only need
+        // the digest anyway, we decompose the trie and digest out of `digest`.
+        let persisted_digest = DirectoryDigest::from_persisted_digest(digest.as_digest());
+        if let Some(digest_trie) = digest.tree {
+            self
+                .store
+                .record_digest_trie(digest_trie, true)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        self
+            .sandboxer
+            .materialize_directory(
+                &workdir_path,
+                workdir_root_path,
+                &persisted_digest,
+                mutable_paths,
+            )
+            .await
+            .map_err(|e| format!("materialize_directory() request to sandboxer process failed: {e}"))
+    }
+}
+
+/// Materializes directly via the `Store`, in-process.
+pub struct StoreMaterializer<'a> {
+    pub store: &'a Store,
+}
+
+#[async_trait]
+impl Materializer for StoreMaterializer<'_> {
+    async fn materialize(
+        &self,
+        workdir_path: PathBuf,
+        workdir_root_path: &Path,
+        digest: DirectoryDigest,
+        mutable_paths: &BTreeSet<RelativePath>,
+    ) -> Result<(), String> {
+        debug!("Materializing directly to {workdir_path:?}: {digest:#?}");
+        self
+            .store
+            .materialize_directory(
+                workdir_path,
+                workdir_root_path,
+                digest,
+                false,
+                mutable_paths,
+                Permissions::Writable,
+            )
+            .await
+            .map_err(|se| se.to_string())
+    }
+}
+
 /// Prepares the given workdir for use by the given Process.
 ///
 /// Returns true if the executable for the Process was created in the workdir, indicating that
@@ -672,7 +1800,7 @@ pub async fn prepare_workdir(
     req: &Process,
     materialized_input_digest: DirectoryDigest,
     store: &Store,
-    sandboxer: Option<&Sandboxer>,
+    materializer: &dyn Materializer,
     named_caches: &NamedCaches,
     immutable_inputs: &ImmutableInputs,
     named_caches_prefix: Option<&Path>,
@@ -708,52 +1836,19 @@ pub async fn prepare_workdir(
         let mut mutable_paths = req.output_files.clone();
         mutable_paths.extend(req.output_directories.clone());
 
-        if let Some(sandboxer) = sandboxer {
-            debug!(
-                "Materializing via sandboxer to {:?}: {:#?}",
-                &workdir_path, &complete_input_digest
-            );
-            // Ensure that the tree is persisted in the store, so that the sandboxer
-            // can materialize it from there.  Since record_digest_trie() takes ownership of its
-            // argument, and we only need the digest anyway, we decompose the trie and digest
-            // out of complete_input_digest.
-            let persisted_digest =
-                DirectoryDigest::from_persisted_digest(complete_input_digest.as_digest());
-            if let Some(digest_trie) = complete_input_digest.tree {
-                store
-                    .record_digest_trie(digest_trie, true)
-                    .await?;
-            }
-            sandboxer
-                .materialize_directory(
-                    &workdir_path,
-                    workdir_root_path,
-                    &persisted_digest,
-                    &mutable_paths,
-                )
-                .await
-                .map_err(|e| {
-                    format!(
-                        "materialize_directory() request to sandboxer process failed: {e}"
-                    )
-                })?;
-        } else {
-            debug!(
-                "Materializing directly to {:?}: {:#?}",
-                &workdir_path, &complete_input_digest
-            );
-            store
-                .materialize_directory(
-                    workdir_path.clone(),
-                    workdir_root_path,
-                    complete_input_digest,
-                    false,
-                    &mutable_paths,
-                    Permissions::Writable,
+        materializer
+            .materialize(
+                workdir_path.clone(),
+                workdir_root_path,
+                complete_input_digest,
+                &mutable_paths,
+            )
+            .await
+            .map_err(|e| {
+                format!(
+                    "An error occurred when attempting to materialize a working directory at {workdir_path:#?}: {e}"
                 )
-                .await
-                .map_err(|se| se.enrich(format!("An error occurred when attempting to materialize a working directory at {workdir_path:#?}").as_str()).to_string())?;
-        }
+            })?;
 
         if let Some(executable_path) = maybe_executable_path {
             Ok(tokio::fs::metadata(executable_path).await.is_ok())
@@ -790,6 +1885,12 @@ pub fn create_sandbox(
 
 /// Dropping sandboxes can involve a lot of IO, so it is spawned to the background as a blocking
 /// task.
+///
+/// NB: Callers are expected to only keep/drop this after `ProcessGroupGuard` has signaled
+/// down and waited out the process (and any process group it led): otherwise a still running
+/// descendant could keep the directory open, or write into it, while it is being deleted. Note that
+/// `ProcessGroupGuard`'s wait is itself best-effort and bounded (see its own doc comment) -- a
+/// re-parented grandchild or a member stuck in uninterruptible sleep can in principle outlive it.
 #[must_use]
 pub struct AsyncDropSandbox(Executor, PathBuf, Option<TempDir>);
 
@@ -822,64 +1923,171 @@ impl Drop for AsyncDropSandbox {
     }
 }
 
-/// Create a file called __run.sh with the env, cwd and argv used by Pants to facilitate debugging.
+/// Creates the "reproduce what Pants did" debugging script(s) for the current platform: a POSIX
+/// `sh` script on Unix, and both a PowerShell `__run.ps1` and a `__run.cmd` batch script on
+/// Windows. Unlike a single hardcoded bash script, each variant picks the quoting implementation
+/// that's actually correct for its shell, so the affordance isn't misleading under e.g. `dash` or
+/// `cmd.exe`. If `input_digest` is given, also writes `__digest.txt` recording the fingerprint and
+/// size of the Process' input digest, so that a sandbox preserved under `KeepSandboxes::OnFailure`
+/// and later cleaned up can still have its inputs identified and re-materialized from the store.
 pub fn setup_run_sh_script(
     sandbox_path: &Path,
     env: &BTreeMap<String, String>,
     working_directory: &Option<RelativePath>,
     argv: &[String],
     workdir_path: &Path,
+    input_digest: Option<&DirectoryDigest>,
 ) -> Result<(), String> {
-    let mut env_var_strings: Vec<String> = vec![];
-    for (key, value) in env.iter() {
-        let quoted_arg = Bash::quote_vec(value.as_str());
-        let arg_str = str::from_utf8(&quoted_arg)
-            .map_err(|e| format!("{e:?}"))?
-            .to_string();
-        let formatted_assignment = format!("{key}={arg_str}");
-        env_var_strings.push(formatted_assignment);
-    }
-    let stringified_env_vars: String = env_var_strings.join(" ");
-
-    // Shell-quote every command-line argument, as necessary.
-    let mut full_command_line: Vec<String> = vec![];
-    for arg in argv.iter() {
-        let quoted_arg = Bash::quote_vec(arg.as_str());
-        let arg_str = str::from_utf8(&quoted_arg)
-            .map_err(|e| format!("{e:?}"))?
-            .to_string();
-        full_command_line.push(arg_str);
-    }
-
-    let stringified_cwd = {
-        let cwd = if let Some(ref working_directory) = working_directory {
-            workdir_path.join(working_directory)
-        } else {
-            workdir_path.to_owned()
-        };
-        let quoted_cwd = Bash::quote_vec(cwd.as_os_str());
-        str::from_utf8(&quoted_cwd)
-            .map_err(|e| format!("{e:?}"))?
-            .to_string()
+    let cwd = match working_directory {
+        Some(working_directory) => workdir_path.join(working_directory),
+        None => workdir_path.to_owned(),
     };
 
-    let stringified_command_line: String = full_command_line.join(" ");
-    let full_script = format!(
-        "#!/usr/bin/env bash
+    #[cfg(unix)]
+    write_posix_sh_reproducer(sandbox_path, env, &cwd, argv)?;
+    #[cfg(windows)]
+    {
+        write_powershell_reproducer(sandbox_path, env, &cwd, argv)?;
+        write_cmd_reproducer(sandbox_path, env, &cwd, argv)?;
+    }
+
+    if let Some(input_digest) = input_digest {
+        let digest = input_digest.as_digest();
+        write_script(
+            sandbox_path,
+            "__digest.txt",
+            &format!("{} {}\n", digest.hash, digest.size_bytes),
+            false,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Quotes `value` for inclusion in a POSIX `sh` command line: wraps it in single quotes, escaping
+/// any embedded single quote as `'\''`. Unlike bash-specific quoting (e.g. ANSI-C `$'...'`
+/// escapes), this is portable to any POSIX-compatible shell (dash, ash, busybox `sh`, ...).
+fn posix_sh_quote(value: impl AsRef<str>) -> String {
+    format!("'{}'", value.as_ref().replace('\'', r"'\''"))
+}
+
+#[cfg(unix)]
+fn write_posix_sh_reproducer(
+    sandbox_path: &Path,
+    env: &BTreeMap<String, String>,
+    cwd: &Path,
+    argv: &[String],
+) -> Result<(), String> {
+    let env_assignments = env
+        .iter()
+        .map(|(key, value)| format!("{key}={}", posix_sh_quote(value)))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let command_line = argv
+        .iter()
+        .map(posix_sh_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let quoted_cwd = posix_sh_quote(cwd.to_string_lossy());
+
+    let script = format!(
+        "#!/bin/sh
 # This command line should execute the same process as pants did internally.
-cd {stringified_cwd}
-env -i {stringified_env_vars} {stringified_command_line}
+cd {quoted_cwd}
+env -i {env_assignments} {command_line}
 ",
     );
 
-    let full_file_path = sandbox_path.join("__run.sh");
+    write_script(sandbox_path, "__run.sh", &script, true)
+}
+
+/// Quotes `value` for inclusion in a PowerShell command line: wraps it in single quotes (verbatim
+/// string literals), doubling any embedded single quote.
+#[cfg(windows)]
+fn powershell_quote(value: impl AsRef<str>) -> String {
+    format!("'{}'", value.as_ref().replace('\'', "''"))
+}
+
+#[cfg(windows)]
+fn write_powershell_reproducer(
+    sandbox_path: &Path,
+    env: &BTreeMap<String, String>,
+    cwd: &Path,
+    argv: &[String],
+) -> Result<(), String> {
+    let env_lines: String = env
+        .iter()
+        .map(|(key, value)| format!("$env:{key} = {}\n", powershell_quote(value)))
+        .collect();
+    let command_line = argv
+        .iter()
+        .map(powershell_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let script = format!(
+        "# This command line should execute the same process as pants did internally.
+{env_lines}Set-Location {}
+{command_line}
+",
+        powershell_quote(cwd.to_string_lossy()),
+    );
+
+    write_script(sandbox_path, "__run.ps1", &script, false)
+}
+
+/// Quotes `value` for inclusion in a `cmd.exe` command line: wraps it in double quotes, doubling
+/// any embedded double quote.
+#[cfg(windows)]
+fn cmd_quote(value: impl AsRef<str>) -> String {
+    format!("\"{}\"", value.as_ref().replace('"', "\"\""))
+}
+
+#[cfg(windows)]
+fn write_cmd_reproducer(
+    sandbox_path: &Path,
+    env: &BTreeMap<String, String>,
+    cwd: &Path,
+    argv: &[String],
+) -> Result<(), String> {
+    let env_lines: String = env
+        .iter()
+        .map(|(key, value)| format!("set {key}={value}\r\n"))
+        .collect();
+    let command_line = argv
+        .iter()
+        .map(cmd_quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let script = format!(
+        "@echo off\r\nrem This command line should execute the same process as pants did internally.\r\n{env_lines}cd /d {}\r\n{command_line}\r\n",
+        cmd_quote(cwd.to_string_lossy()),
+    );
+
+    write_script(sandbox_path, "__run.cmd", &script, false)
+}
+
+/// Writes `contents` to `sandbox_path`/`file_name`, making it user-executable if `executable`.
+fn write_script(
+    sandbox_path: &Path,
+    file_name: &str,
+    contents: &str,
+    executable: bool,
+) -> Result<(), String> {
+    let full_file_path = sandbox_path.join(file_name);
+    let mut options = std::fs::OpenOptions::new();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    if executable {
+        options.mode(USER_EXECUTABLE_MODE); // Executable for user, read-only for others.
+    }
+    #[cfg(not(unix))]
+    let _ = executable;
 
-    std::fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .mode(USER_EXECUTABLE_MODE) // Executable for user, read-only for others.
+    options
         .open(full_file_path)
         .map_err(|e| format!("{e:?}"))?
-        .write_all(full_script.as_bytes())
+        .write_all(contents.as_bytes())
         .map_err(|e| format!("{e:?}"))
 }